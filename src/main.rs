@@ -1,21 +1,27 @@
-use std::convert::{Infallible, TryInto};
-use std::collections::HashMap;
+use std::convert::Infallible;
+use std::collections::{HashMap, HashSet};
 use std::env;
 use std::fmt::{self, Display};
+use std::fs::{self, File};
+use std::io::{self, BufRead, Write};
 use std::marker::PhantomData;
 use std::ops::Deref;
 use std::path::PathBuf;
 use std::str::FromStr;
+use std::time::Duration;
 
 use chrono::Utc;
-use color_eyre::{eyre::WrapErr, Help, owo_colors::OwoColorize, Result};
+use color_eyre::{eyre::{eyre, WrapErr}, Help, owo_colors::OwoColorize, Result};
 use egg_mode::{auth, tweet, user, KeyPair, Token};
 use futures::StreamExt;
 use once_cell::unsync::OnceCell;
 use petgraph::graphmap::DiGraphMap;
 use rand::{Rng, thread_rng};
+use serde::{Deserialize, Serialize};
 use structopt::StructOpt;
 
+mod search_cursor;
+
 trait EnvVarOrArg {
     const NAME: &'static str;
     const VAR_NAME: &'static str;
@@ -98,15 +104,36 @@ env_var_arg! {
     ),
 }
 
+/// Interactive subcommands that don't crawl a thread themselves.
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Run the PIN-based 3-legged OAuth dance to obtain a user-context
+    /// access token and cache it to disk for future runs.
+    Auth,
+}
+
 #[derive(Debug, StructOpt)]
 struct Args {
+    #[structopt(subcommand)]
+    command: Option<Command>,
+
     /// The root of the twitter thread to crawl.
-    root_tweet_id: u64,
+    ///
+    /// Not needed (and ignored) when running the `auth` subcommand.
+    root_tweet_id: Option<u64>,
 
-    /// Output file for the graph (graphviz dot); stdout if not given.
+    /// Output file for the graph (graphviz dot). If not given, no DOT is
+    /// written; see `--tree`, which is the default in that case.
     #[structopt(short = "o", long = "output", parse(from_os_str))]
     output: Option<PathBuf>,
 
+    /// Render the reply tree to the terminal as an indented, colored tree.
+    ///
+    /// This is the default when `--output` isn't given; pass it explicitly
+    /// to get both a DOT file and a terminal rendering.
+    #[structopt(long = "tree")]
+    tree: bool,
+
     #[structopt(default_value)]
     /// Twitter API consumer key. Must be authorized to use the V2 API.
     ///
@@ -118,9 +145,44 @@ struct Args {
     /// If not specified this is grabbed from `$TWITTER_CONSUMER_SECRET`.
     #[structopt(default_value)]
     consumer_secret: ArgWithEnvVarDefault<ConsumerSecret>,
+
+    /// Twitter API access token for user-context requests.
+    ///
+    /// Needed to crawl protected or otherwise access-limited threads. If not
+    /// given, falls back to `$TWITTER_ACCESS_TOKEN`, then to the token
+    /// cached by `tweet-tree auth`, then to an app-only bearer token.
+    #[structopt(long = "access-token", env = "TWITTER_ACCESS_TOKEN")]
+    access_token: Option<String>,
+
+    /// Secret paired with `--access-token` / `$TWITTER_ACCESS_TOKEN_SECRET`.
+    #[structopt(long = "access-token-secret", env = "TWITTER_ACCESS_TOKEN_SECRET")]
+    access_token_secret: Option<String>,
+
+    /// Path to a JSON cache of previously fetched users and tweets.
+    ///
+    /// Consulted before every user/tweet lookup and updated as the crawl
+    /// discovers new nodes, so an interrupted or rate-limited crawl can
+    /// resume where it left off instead of refetching everything.
+    #[structopt(long = "cache", parse(from_os_str))]
+    cache: Option<PathBuf>,
+
+    /// Ignore any existing `--cache` file and crawl from scratch.
+    ///
+    /// The cache file (if given) is still written back to, overwriting
+    /// whatever was cached before.
+    #[structopt(long = "refresh")]
+    refresh: bool,
+
+    /// Upper bound, in seconds, on how long a single rate-limit backoff is
+    /// allowed to sleep for before the next search call is retried.
+    #[structopt(long = "max-wait", default_value = "900")]
+    max_wait: u64,
 }
 
-#[derive(Debug)]
+type TweetId = u64;
+type UserId = u64;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct User {
     handle: String,
     name: String,
@@ -159,55 +221,375 @@ impl User {
     }
 }
 
+/// An access-token/secret pair, cached on disk so the PIN-based OAuth dance
+/// doesn't need to be repeated on every run.
+#[derive(Debug, Serialize, Deserialize)]
+struct CachedToken {
+    key: String,
+    secret: String,
+}
+
+/// Where we cache the access token obtained via `tweet-tree auth`.
+fn token_cache_path() -> Result<PathBuf> {
+    let mut dir = dirs::config_dir()
+        .ok_or_else(|| eyre!("Unable to determine the user's config directory"))?;
+    dir.push("tweet-tree");
+    Ok(dir.join("token.json"))
+}
+
+fn load_cached_access_token() -> Result<Option<KeyPair>> {
+    let path = token_cache_path()?;
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    let cached: CachedToken = serde_json::from_str(&fs::read_to_string(&path)?)
+        .wrap_err_with(|| format!("Unable to parse cached token at `{}`", path.display()))?;
+    Ok(Some(KeyPair::new(cached.key, cached.secret)))
+}
+
+fn cache_access_token(access: &KeyPair) -> Result<PathBuf> {
+    let path = token_cache_path()?;
+    fs::create_dir_all(path.parent().unwrap())?;
+    fs::write(
+        &path,
+        serde_json::to_vec_pretty(&CachedToken {
+            key: access.key.to_string(),
+            secret: access.secret.to_string(),
+        })?,
+    )?;
+    Ok(path)
+}
+
+/// Walks the user through the PIN-based 3-legged OAuth flow: get a request
+/// token, have the user authorize it in their browser, then trade the PIN
+/// they're given for an access token/secret pair.
+async fn authorize_interactively(consumer: &KeyPair) -> Result<KeyPair> {
+    let request_token = auth::request_token(consumer, "oob")
+        .await
+        .wrap_err("Unable to obtain a request token")?;
+
+    let authorize_url = auth::authorize_url(&request_token);
+    eprintln!(
+        "{}: open this URL and authorize {}, then paste the PIN it gives you below:\n\n    {}\n",
+        "Authorize".italic().blue(),
+        "tweet-tree".bold(),
+        authorize_url.underline(),
+    );
+
+    eprint!("PIN: ");
+    io::stderr().flush()?;
+    let mut pin = String::new();
+    io::stdin().lock().read_line(&mut pin)?;
+
+    let (token, _user_id, _screen_name) = auth::access_token(consumer.clone(), &request_token, pin.trim())
+        .await
+        .wrap_err("Unable to exchange the PIN for an access token")?;
+
+    match token {
+        Token::Access { access, .. } => Ok(access),
+        Token::Bearer(_) => unreachable!("`auth::access_token` always returns a user-context token"),
+    }
+}
+
+/// How a reply-tree edge came to exist: a direct reply, a quote-tweet, or a
+/// retweet of a tweet already in the graph.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+enum EdgeKind {
+    Reply,
+    Quote,
+    Retweet,
+}
+
+/// The weight of an edge in the reply graph: who made the referencing tweet
+/// and how it references the one it points at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+struct Edge {
+    author: UserId,
+    kind: EdgeKind,
+}
+
+/// What we keep about each tweet: who wrote it and its body (the latter is
+/// used, truncated, in the `--tree` rendering and the DOT node labels).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct TweetInfo {
+    author: UserId,
+    body: String,
+}
+
+/// On-disk record of everything fetched so far, so a crawl can resume
+/// without refetching users/tweets it already knows about.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Cache {
+    users: HashMap<UserId, (User, usize)>,
+    tweets: HashMap<TweetId, TweetInfo>,
+    edges: Vec<(TweetId, TweetId, Edge)>,
+}
+
+impl Cache {
+    fn load(path: &PathBuf) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let contents = fs::read_to_string(path)
+            .wrap_err_with(|| format!("Unable to read cache file `{}`", path.display()))?;
+        serde_json::from_str(&contents)
+            .wrap_err_with(|| format!("Unable to parse cache file `{}`", path.display()))
+    }
+
+    fn save(
+        path: &PathBuf,
+        users: &HashMap<UserId, (User, usize)>,
+        tweets: &HashMap<TweetId, TweetInfo>,
+        graph: &DiGraphMap<TweetId, Edge>,
+    ) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let cache = Cache {
+            users: users.clone(),
+            tweets: tweets.clone(),
+            edges: graph.all_edges().map(|(from, to, &edge)| (from, to, edge)).collect(),
+        };
+
+        fs::write(path, serde_json::to_vec_pretty(&cache)?)
+            .wrap_err_with(|| format!("Unable to write cache file `{}`", path.display()))
+    }
+}
+
+/// Escapes a string for use inside a GraphViz double-quoted DOT string.
+fn dot_escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Writes the crawled reply graph out as a GraphViz DOT document, grouping
+/// each user's tweets into a `subgraph cluster_<uid>` colored with their
+/// `User::color`.
+fn write_dot(
+    out: &mut dyn Write,
+    graph: &DiGraphMap<TweetId, Edge>,
+    tweets: &HashMap<TweetId, TweetInfo>,
+    users: &HashMap<UserId, (User, usize)>,
+    root: TweetId,
+) -> io::Result<()> {
+    writeln!(out, "digraph tweet_tree {{")?;
+    writeln!(out, "    rankdir=TB;")?;
+
+    for (&uid, (user, _)) in users {
+        let (r, g, b) = user.color;
+        let cluster_label = dot_escape(&format!("{} (@{})", user.name, user.handle));
+
+        writeln!(out, "    subgraph cluster_{} {{", uid)?;
+        writeln!(out, "        label = \"{}\";", cluster_label)?;
+        writeln!(out, "        color = \"#{:02x}{:02x}{:02x}\";", r, g, b)?;
+
+        for (&tid, info) in tweets {
+            if info.author == uid {
+                let shape = if tid == root { "doublecircle" } else { "ellipse" };
+                let node_label = format!(
+                    "{} (@{})\\n{}: {}",
+                    dot_escape(&user.name),
+                    dot_escape(&user.handle),
+                    tid,
+                    dot_escape(&truncate_body(&info.body, 40)),
+                );
+                writeln!(out, "        {} [label=\"{}\", shape={}];", tid, node_label, shape)?;
+            }
+        }
+
+        writeln!(out, "    }}")?;
+    }
+
+    for (from, to, &edge) in graph.all_edges() {
+        let (user, _) = &users[&edge.author];
+        let (r, g, b) = user.color;
+        let style = match edge.kind {
+            EdgeKind::Reply => "solid",
+            EdgeKind::Quote => "dashed",
+            EdgeKind::Retweet => "dotted",
+        };
+        writeln!(
+            out,
+            "    {} -> {} [color=\"#{:02x}{:02x}{:02x}\", style={}];",
+            from, to, r, g, b, style,
+        )?;
+    }
+
+    writeln!(out, "}}")
+}
+
+/// Truncates `s` to at most `max_chars` characters, appending an ellipsis if
+/// it was cut short, and flattens it to a single line.
+fn truncate_body(s: &str, max_chars: usize) -> String {
+    let mut flattened: String = s.split_whitespace().collect::<Vec<_>>().join(" ");
+    if flattened.chars().count() > max_chars {
+        flattened = flattened.chars().take(max_chars).collect();
+        flattened.push('\u{2026}');
+    }
+    flattened
+}
+
+/// Renders the reply tree as an indented, colored tree using box-drawing
+/// connectors, walking the graph depth-first from `root`.
+///
+/// Guards against revisiting a node, so a malformed (cyclic) graph can't
+/// make this loop forever.
+fn write_tree(
+    out: &mut dyn Write,
+    graph: &DiGraphMap<TweetId, Edge>,
+    tweets: &HashMap<TweetId, TweetInfo>,
+    users: &HashMap<UserId, (User, usize)>,
+    root: TweetId,
+) -> io::Result<()> {
+    #[allow(clippy::too_many_arguments)]
+    fn walk(
+        out: &mut dyn Write,
+        graph: &DiGraphMap<TweetId, Edge>,
+        tweets: &HashMap<TweetId, TweetInfo>,
+        users: &HashMap<UserId, (User, usize)>,
+        node: TweetId,
+        prefix: &str,
+        is_last: bool,
+        is_root: bool,
+        visited: &mut HashSet<TweetId>,
+    ) -> io::Result<()> {
+        if !visited.insert(node) {
+            return Ok(());
+        }
+
+        let connector = if is_root {
+            ""
+        } else if is_last {
+            "└─ "
+        } else {
+            "├─ "
+        };
+
+        let info = &tweets[&node];
+        let (user, _) = &users[&info.author];
+        writeln!(out, "{}{}{}: {}", prefix, connector, user, truncate_body(&info.body, 80))?;
+
+        let mut children: Vec<TweetId> = graph
+            .neighbors_directed(node, petgraph::Direction::Outgoing)
+            .collect();
+        children.sort_unstable();
+
+        let child_prefix = if is_root {
+            String::new()
+        } else if is_last {
+            format!("{}   ", prefix)
+        } else {
+            format!("{}│  ", prefix)
+        };
+
+        let last_index = children.len().saturating_sub(1);
+        for (i, child) in children.into_iter().enumerate() {
+            walk(out, graph, tweets, users, child, &child_prefix, i == last_index, false, visited)?;
+        }
+
+        Ok(())
+    }
+
+    let mut visited = HashSet::new();
+    walk(out, graph, tweets, users, root, "", true, true, &mut visited)
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     color_eyre::install()?;
     let args = Args::from_args();
 
-    let token = KeyPair::new(
+    let consumer = KeyPair::new(
         (*args.consumer_key).clone(),
         (*args.consumer_secret).clone(),
     );
-    let token = auth::bearer_token(&token)
-        .await
-        .wrap_err("Unable to authenticate!")
-        .suggestion("check your consumer key/consumer secret?")?;
 
-    let root = tweet::show(args.root_tweet_id, &token)
-        .await
-        .wrap_err_with(|| format!("Failed to find the specified root tweet (`{}`)", args.root_tweet_id))?;
+    if let Some(Command::Auth) = args.command {
+        let access = authorize_interactively(&consumer).await?;
+        let path = cache_access_token(&access)?;
+        eprintln!("{}: cached access token to `{}`", "Auth".italic().green(), path.display());
+        return Ok(());
+    }
 
-    if Utc::now().signed_duration_since(root.created_at).num_days() >= 7 {
-        eprintln!(
-            "{}: The given root tweet is {}!\n\n\
-            The Twitter Recent Search API will not find tweets that are over \
-            seven days old.\n\
-            The Full-archive Search API will but that API is currently limited \
-            to Academic Research users only.\n\n\
-            See this page for more details: {}.",
-            "WARNING".yellow().bold(),
-            "over 7 days old".bold().italic(),
-            "https://developer.twitter.com/en/docs/twitter-api/tweets/search/introduction".underline().italic(),
-        );
+    let root_tweet_id = args.root_tweet_id
+        .ok_or_else(|| eyre!("The root tweet id is required unless running `tweet-tree auth`"))?;
+
+    let token = if let (Some(key), Some(secret)) = (&args.access_token, &args.access_token_secret) {
+        Token::Access { consumer, access: KeyPair::new(key.clone(), secret.clone()) }
+    } else if let Some(access) = load_cached_access_token()? {
+        Token::Access { consumer, access }
+    } else {
+        auth::bearer_token(&consumer)
+            .await
+            .wrap_err("Unable to authenticate!")
+            .suggestion("check your consumer key/consumer secret?")?
+    };
+
+    let cache = match &args.cache {
+        Some(path) if !args.refresh => Cache::load(path)?,
+        _ => Cache::default(),
+    };
+
+    let mut users = cache.users;
+    let mut tweets = cache.tweets;
+
+    let mut graph = DiGraphMap::<TweetId, Edge>::new();
+    for (from, to, edge) in cache.edges {
+        graph.add_edge(from, to, edge);
     }
 
-    type TweetId = u64;
-    type UserId = u64;
+    let root_was_cached = tweets.contains_key(&root_tweet_id);
+    let root_user_id = if let Some(info) = tweets.get(&root_tweet_id) {
+        info.author
+    } else {
+        let root = tweet::show(root_tweet_id, &token)
+            .await
+            .wrap_err_with(|| format!("Failed to find the specified root tweet (`{}`)", root_tweet_id))?;
+
+        if Utc::now().signed_duration_since(root.created_at).num_days() >= 7 {
+            eprintln!(
+                "{}: The given root tweet is {}!\n\n\
+                The Twitter Recent Search API will not find tweets that are over \
+                seven days old.\n\
+                The Full-archive Search API will but that API is currently limited \
+                to Academic Research users only.\n\n\
+                See this page for more details: {}.",
+                "WARNING".yellow().bold(),
+                "over 7 days old".bold().italic(),
+                "https://developer.twitter.com/en/docs/twitter-api/tweets/search/introduction".underline().italic(),
+            );
+        }
+
+        let root_user_id = root.user.as_ref().unwrap().id;
+        tweets.insert(root_tweet_id, TweetInfo { author: root_user_id, body: root.text.clone() });
+        root_user_id
+    };
 
-    let mut users = HashMap::<UserId, (User, usize)>::new();
-    let root_user_id = root.user.as_ref().unwrap().id;
-    users.insert(root_user_id, (User::new(root_user_id, &token).await?, 1));
+    if !users.contains_key(&root_user_id) {
+        users.insert(root_user_id, (User::new(root_user_id, &token).await?, 0));
+    }
+    if !root_was_cached {
+        users.get_mut(&root_user_id).unwrap().1 += 1;
+    }
 
-    let mut tweets = HashMap::<TweetId, UserId>::new();
-    tweets.insert(args.root_tweet_id, root_user_id);
+    graph.add_node(root_tweet_id);
 
-    let mut graph = DiGraphMap::<TweetId, UserId>::new();
-    graph.add_node(args.root_tweet_id);
+    if let Some(path) = &args.cache {
+        Cache::save(path, &users, &tweets, &graph)?;
+    }
 
-    let mut children = tweet::all_children_raw(args.root_tweet_id, &token).await;
+    let mut children = search_cursor::replies_to(root_tweet_id, &token)
+        .with_max_wait(Some(Duration::from_secs(args.max_wait)));
     while let Some(t) = children.next().await {
         let t = t?;
         let author_id = t.author_id.unwrap();
+        let t: tweet::Tweet = (*t).clone();
+
+        if tweets.contains_key(&t.id) {
+            continue;
+        }
 
         let (_, ref mut count) = if let Some(p) = users.get_mut(&author_id) {
             p
@@ -217,14 +599,41 @@ async fn main() -> Result<()> {
         };
         *count += 1;
 
-        let t: tweet::Tweet = (*t).clone().try_into()?;
-        tweets.insert(t.id, author_id);
+        tweets.insert(t.id, TweetInfo { author: author_id, body: t.text.clone() });
 
-        let prev = t.in_reply_to_status_id.unwrap();
-        graph.add_edge(prev, t.id, author_id);
+        if let Some(prev) = t.in_reply_to_status_id {
+            graph.add_edge(prev, t.id, Edge { author: author_id, kind: EdgeKind::Reply });
+        }
+
+        if let Some(quoted_id) = t.quoted_status_id {
+            if graph.contains_node(quoted_id) {
+                graph.add_edge(quoted_id, t.id, Edge { author: author_id, kind: EdgeKind::Quote });
+            }
+        }
+        if let Some(retweeted) = &t.retweeted_status {
+            if graph.contains_node(retweeted.id) {
+                graph.add_edge(retweeted.id, t.id, Edge { author: author_id, kind: EdgeKind::Retweet });
+            }
+        }
+
+        if let Some(path) = &args.cache {
+            Cache::save(path, &users, &tweets, &graph)?;
+        }
     }
 
     eprintln!("{} tweets found! ({} unique users)", graph.node_count(), users.len());
 
+    if let Some(path) = &args.output {
+        let mut out = File::create(path)
+            .wrap_err_with(|| format!("Unable to create output file `{}`", path.display()))?;
+        write_dot(&mut out, &graph, &tweets, &users, root_tweet_id)
+            .wrap_err("Failed to write the graph out as GraphViz DOT")?;
+    }
+
+    if args.tree || args.output.is_none() {
+        write_tree(&mut io::stdout(), &graph, &tweets, &users, root_tweet_id)
+            .wrap_err("Failed to render the reply tree")?;
+    }
+
     Ok(())
 }
@@ -1,4 +1,4 @@
-//! Like [`eggmode::cursor`], but for search endpoints.
+//! Like [`egg_mode::cursor`], but for search endpoints.
 //!
 //! Unfortunately (but for good reasons) the Twitter V2 Search API has a
 //! slightly [different pagination scheme][page] than other V1 endpoints.
@@ -6,18 +6,32 @@
 //! fields used are slightly different (`max_results` instead of `count`,
 //! `next_token` instead of `next_cursor`/`cursor`).
 //!
-//! The code for [`eggmode::cursor::CursorIter`] and [`eggmode::cursor::Cursor`]
+//! The code for [`egg_mode::cursor::CursorIter`] and [`egg_mode::cursor::Cursor`]
 //! is largely duplicated here. If this code is ever to be merged upstream, it'd
 //! be worth looking into unifying this with `CursorIter` and `Cursor`, though
 //! I don't know of a good way to do so.
 //!
 //! [page]: https://developer.twitter.com/en/docs/twitter-api/tweets/search/integrate/paginate
 
-use eggmode::auth;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use egg_mode::error::{Error, Result};
+use egg_mode::raw::{get, request_with_json_response, FutureResponse, ParamList};
+use egg_mode::tweet::Tweet;
+use egg_mode::{auth, RateLimitStatus, Response};
+use futures::Stream;
+use owo_colors::OwoColorize;
 use serde::{de::DeserializeOwned, Deserialize};
+use tokio::time::Sleep;
 
-// We currently only have a single wrapper search result type so we don't
-// _really_ need this trait but I figured we might as well.
+/// A page of V2 search results: the matched items plus the `next_token`
+/// needed to fetch the next page (if any).
+///
+/// We currently only have a single wrapper search result type so we don't
+/// _really_ need this trait but I figured we might as well.
 pub trait SearchCursor {
     type Item;
 
@@ -28,99 +42,146 @@ pub trait SearchCursor {
 
 #[derive(Debug, Deserialize)]
 struct SearchResultsMeta {
-    newest_id: u64,
-    oldest_id: u64,
+    #[serde(default)]
+    newest_id: Option<u64>,
+    #[serde(default)]
+    oldest_id: Option<u64>,
+    #[serde(default)]
     result_count: usize,
     next_token: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
-struct SearchResults<I: Deserialize> {
+pub struct SearchResults<I> {
+    #[serde(default)]
     data: Vec<I>,
     meta: SearchResultsMeta,
 }
 
+impl<I> SearchCursor for SearchResults<I> {
+    type Item = I;
+
+    fn next_token(&self) -> Option<&str> {
+        self.meta.next_token.as_deref()
+    }
+
+    fn into_inner(self) -> Vec<I> {
+        self.data
+    }
+}
+
 #[must_use = "cursor iterators are lazy and do nothing unless consumed"]
 pub struct SearchCursorIter<T>
 where
-    T: Cursor + DeserializeOwned,
+    T: SearchCursor + DeserializeOwned,
 {
     link: &'static str,
     token: auth::Token,
     params_base: Option<ParamList>,
+    page_size: Option<i32>,
+    max_wait: Option<Duration>,
 
-    pub next_token: i64,
+    pub next_token: Option<String>,
 
+    rate_limit: Option<RateLimitStatus>,
     loader: Option<FutureResponse<T>>,
-    iter: Option<Box<dyn Iterator<Item = Response<T::Item>>>>,
+    sleep: Option<Pin<Box<Sleep>>>,
+    iter: Option<std::vec::IntoIter<Response<T::Item>>>,
 }
 
-impl<T> CursorIter<T>
+impl<T> SearchCursorIter<T>
 where
-    T: Cursor + DeserializeOwned,
+    T: SearchCursor + DeserializeOwned,
 {
-    ///Sets the number of results returned in a single network call.
-    ///
-    ///Certain calls set their own minimums and maximums for what this value can be. Furthermore,
-    ///some calls don't allow you to set the size of the pages at all. Refer to the individual
-    ///methods' documentation for specifics. If this method is called for a response that does not
-    ///accept changing the page size, no change to the underlying struct will occur.
+    /// Sets the number of results returned in a single network call.
     ///
-    ///Calling this function will invalidate any current results, if any were previously loaded.
-    pub fn with_page_size(self, page_size: i32) -> CursorIter<T> {
-        if self.page_size.is_some() {
-            CursorIter {
-                page_size: Some(page_size),
-                previous_cursor: -1,
-                next_cursor: -1,
-                loader: None,
-                iter: None,
-                ..self
-            }
-        } else {
-            self
+    /// Calling this function will invalidate any current results, if any were previously loaded.
+    pub fn with_page_size(self, page_size: i32) -> SearchCursorIter<T> {
+        SearchCursorIter {
+            page_size: Some(page_size),
+            next_token: None,
+            loader: None,
+            iter: None,
+            ..self
+        }
+    }
+
+    /// Bounds how long a single rate-limit backoff (see the `Stream` impl)
+    /// is allowed to sleep for. `None` means wait however long the API says
+    /// the window needs to reset.
+    pub fn with_max_wait(self, max_wait: Option<Duration>) -> SearchCursorIter<T> {
+        SearchCursorIter { max_wait, ..self }
+    }
+
+    /// Schedules a sleep until `reset` (clamped to `max_wait`), logging a
+    /// `WARNING`-style notice. Returns `true` if a sleep was scheduled (the
+    /// caller should yield and retry on the next poll), or `false` if there is
+    /// nothing to wait for (the reset has already passed or `max_wait` is
+    /// zero) and the caller can proceed immediately.
+    fn schedule_backoff(&mut self, reset: i32) -> bool {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i32;
+        let until_reset = Duration::from_secs((reset - now).max(0) as u64);
+        let wait = match self.max_wait {
+            Some(max_wait) => until_reset.min(max_wait),
+            None => until_reset,
+        };
+
+        if wait.is_zero() {
+            return false;
         }
+
+        eprintln!(
+            "{}: search rate limit exhausted; sleeping {}s until the window resets...",
+            "WARNING".yellow().bold(),
+            wait.as_secs(),
+        );
+        self.sleep = Some(Box::pin(tokio::time::sleep(wait)));
+        true
     }
 
-    ///Loads the next page of results.
+    /// Loads the next page of results.
     ///
-    ///This is intended to be used as part of this struct's Iterator implementation. It is provided
-    ///as a convenience for those who wish to manage network calls and pagination manually.
+    /// This is intended to be used as part of this struct's Stream implementation. It is
+    /// provided as a convenience for those who wish to manage network calls and pagination
+    /// manually.
     pub fn call(&self) -> impl Future<Output = Result<Response<T>>> {
         let params = ParamList::from(self.params_base.as_ref().cloned().unwrap_or_default())
-            .add_param("cursor", self.next_cursor.to_string())
-            .add_opt_param("count", self.page_size.map_string());
+            .add_opt_param("next_token", self.next_token.clone())
+            .add_opt_param("max_results", self.page_size.map(|s| s.to_string()));
 
         let req = get(self.link, &self.token, Some(&params));
         request_with_json_response(req)
     }
 
-    ///Creates a new instance of CursorIter, with the given parameters and empty initial results.
+    /// Creates a new instance of SearchCursorIter, with the given parameters and empty initial
+    /// results.
     ///
-    ///This is essentially an internal infrastructure function, not meant to be used from consumer
-    ///code.
+    /// This is essentially an internal infrastructure function, not meant to be used from
+    /// consumer code.
     pub(crate) fn new(
         link: &'static str,
         token: &auth::Token,
         params_base: Option<ParamList>,
         page_size: Option<i32>,
-    ) -> CursorIter<T> {
-        CursorIter {
-            link: link,
+    ) -> SearchCursorIter<T> {
+        SearchCursorIter {
+            link,
             token: token.clone(),
-            params_base: params_base,
-            page_size: page_size,
-            previous_cursor: -1,
-            next_cursor: -1,
+            params_base,
+            page_size,
+            max_wait: None,
+            next_token: None,
+            rate_limit: None,
             loader: None,
+            sleep: None,
             iter: None,
         }
     }
 }
 
-impl<T> Stream for CursorIter<T>
+impl<T> Stream for SearchCursorIter<T>
 where
-    T: Cursor + DeserializeOwned + 'static,
+    T: SearchCursor + DeserializeOwned + 'static,
     T::Item: Unpin,
 {
     type Item = Result<Response<T::Item>>;
@@ -133,23 +194,36 @@ where
                     return Poll::Pending;
                 }
                 Poll::Ready(Ok(resp)) => {
-                    self.previous_cursor = resp.previous_cursor_id();
-                    self.next_cursor = resp.next_cursor_id();
+                    self.next_token = resp.next_token().map(str::to_owned);
+                    self.rate_limit = Some(resp.rate_limit_status);
 
                     let resp = Response::map(resp, |r| r.into_inner());
                     let rate = resp.rate_limit_status;
 
-                    let mut iter = Box::new(resp.response.into_iter().map(move |item| Response {
-                        rate_limit_status: rate,
-                        response: item,
-                    }));
+                    let mut iter = resp
+                        .response
+                        .into_iter()
+                        .map(move |item| Response { rate_limit_status: rate, response: item })
+                        .collect::<Vec<_>>()
+                        .into_iter();
                     let first = iter.next();
                     self.iter = Some(iter);
 
                     match first {
                         Some(item) => return Poll::Ready(Some(Ok(item))),
-                        None => return Poll::Ready(None),
+                        None if self.next_token.is_none() => return Poll::Ready(None),
+                        // An empty page doesn't mean we're done: V2 search paginates
+                        // through time windows, not strictly through match count, so
+                        // a `next_token` can still follow a page with zero results.
+                        None => return self.poll_next(cx),
+                    }
+                }
+                Poll::Ready(Err(Error::RateLimit(reset))) => {
+                    if self.schedule_backoff(reset) {
+                        return self.poll_next(cx);
                     }
+                    self.loader = Some(Box::pin(self.call()));
+                    return self.poll_next(cx);
                 }
                 Poll::Ready(Err(e)) => return Poll::Ready(Some(Err(e))),
             }
@@ -158,12 +232,40 @@ where
         if let Some(ref mut results) = self.iter {
             if let Some(item) = results.next() {
                 return Poll::Ready(Some(Ok(item)));
-            } else if self.next_cursor == 0 {
+            } else if self.next_token.is_none() {
                 return Poll::Ready(None);
             }
         }
 
+        if let Some(mut sleep) = self.sleep.take() {
+            match sleep.as_mut().poll(cx) {
+                Poll::Pending => {
+                    self.sleep = Some(sleep);
+                    return Poll::Pending;
+                }
+                Poll::Ready(()) => {}
+            }
+        } else if let Some(rate) = &self.rate_limit {
+            if rate.remaining == 0 {
+                let reset = rate.reset;
+                if self.schedule_backoff(reset) {
+                    return self.poll_next(cx);
+                }
+            }
+        }
+
         self.loader = Some(Box::pin(self.call()));
         self.poll_next(cx)
     }
 }
+
+const RECENT_SEARCH_LINK: &str = "https://api.twitter.com/2/tweets/search/recent";
+
+/// Backs the thread crawl in `main()`: walks the V2 recent-search endpoint
+/// for every tweet whose `conversation_id` matches `root`, paginating with
+/// `next_token` until the search window is exhausted.
+pub fn replies_to(root: u64, token: &auth::Token) -> SearchCursorIter<SearchResults<Tweet>> {
+    let params = ParamList::new().add_param("query", format!("conversation_id:{}", root));
+
+    SearchCursorIter::new(RECENT_SEARCH_LINK, token, Some(params), Some(100))
+}